@@ -1,44 +1,74 @@
-/* This looks like it's not going to be nearly as straightforward as I'd hoped
-Given the requirements of certain DuckDB elements to have certain non-static lifetimes (ex, statements),
-as well as the requirements of Rustler ResourceArcs to have static lifetimes, the drop-in version where
-the values themselves/references to them are shared between both Rust and Elixir, the way they are with the C++ version,
-seems impossible. The best alternitative I can come up with is somehow giving the ResourceArc some data that points to
-some data held only in rust, like maybe a hash key or something, where the data in Rust can be modified while avoiding
-trying to deal with the requirements of ResourceArc. 
-
-
-
-The hashmap idea laid out below doesn't work. It uses global (read: static) variables,
-so the data inserted into them still needs to have a static lifespan.
-Reading what people have done online, most of the solutions assume a "main" function that starts everything else,
-letting you declare a non-static variable there and pass it through to whatever needs it.
-This is a library, so it doesn't have a "main". I'm sure there's a way to do this, but I don't know it.
-
-Current structure for connections:
-RwLock on a HashMap
-HashMap keys are ints, insertion order, value is always unique for up to u64 connections
-HashMap values are Mutex-locked Connections
-
-Upsides:
-should actually function, significant benefit over ResourceArc solution
-can *read* values from many connections at the same time
-thread safety is ensured for the connections by wrapping it in a mutex
-Downsides:
-Only one connection can be modified at any given point in time
-If any connections are actively being read, no connections can be modified
-Unclear how this interacts with items that are made from connections, ex queries
-Only one element may access a given connection in any way at a given point in time
-*/
+//! Every DuckDB `Connection` lives on its own OS thread for its entire
+//! life and never leaves it. `Statement`/`Rows` borrow from `Connection`
+//! with non-'static lifetimes, and rustler's `ResourceArc` requires
+//! `'static` + `Send` + `Sync` data, so there is no way to hand a
+//! `Connection` (or anything borrowing from one) back to Elixir directly.
+//! Instead, NIFs look up an integer id, send a command down a channel to
+//! the thread that owns the relevant connection, and block (under
+//! `DirtyIo`) on a one-shot reply channel carried with the command.
+//! Because a `Connection` and everything it produces live and die inside
+//! a single thread's stack frame, every borrow stays valid for as long as
+//! it's needed and none of it ever crosses a thread boundary.
+//!
+//! `open` doesn't spawn just one connection: DuckDB allows many
+//! connections against one database, so each open database is a `Pool`
+//! (modeled on conduit's SQLite engine) with one writer connection behind
+//! a queue plus a recycling pool of reader connections, so concurrent
+//! `SELECT`s run in parallel with each other and with a writer instead of
+//! serializing through a single lock.
 
-use std::{sync::{Mutex, RwLock}, collections::HashMap};
-use duckdb::{Connection, Rows, Statement, Appender, Config, AccessMode, DefaultOrder, DefaultNullOrder};
-use rustler::{Atom, Env, Term, Encoder, ResourceArc, Decoder};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, RwLock,
+    },
+    thread,
+};
+use duckdb::{
+    types::{ToSqlOutput, Value},
+    Appender, Connection, Rows, Statement, ToSql, Config, AccessMode, DefaultOrder, DefaultNullOrder,
+};
+use rustler::{types::Binary, Atom, Env, Term, Encoder, Decoder};
 
 #[rustler::nif(schedule = "DirtyIo")]
 fn add(a: i64, b: i64) -> i64 {
     a + b
 }
 
+/// A panic inside a NIF while it holds one of the global registry locks
+/// (or a pool's reader mutex) would otherwise poison it, and every NIF after it
+/// would `.unwrap()` that `PoisonError` and take the whole BEAM scheduler
+/// thread down with it. A poisoned lock's data is still perfectly usable
+/// here: `read_lock`/`write_lock`/`lock_mutex` just recover it, the way
+/// std's own atomics-based poisoning eventually did.
+fn read_lock<T>(lock: &RwLock<T>) -> std::sync::RwLockReadGuard<T> {
+    lock.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn write_lock<T>(lock: &RwLock<T>) -> std::sync::RwLockWriteGuard<T> {
+    lock.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn lock_mutex<T>(lock: &Mutex<T>) -> std::sync::MutexGuard<T> {
+    lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Send `command` (built from a fresh one-shot reply channel) to an owner
+/// thread and wait for its reply. Returns a single `Err` instead of
+/// panicking if the owner thread is gone (closed mid-request) rather than
+/// letting a dead channel's `.unwrap()` crash the calling NIF.
+fn roundtrip<T>(
+    commands: &mpsc::Sender<ConnCommand>,
+    command: impl FnOnce(mpsc::Sender<T>) -> ConnCommand,
+) -> Result<T, String> {
+    let (reply, rx) = mpsc::channel();
+    commands
+        .send(command(reply))
+        .map_err(|_| "connection closed".to_string())?;
+    rx.recv().map_err(|_| "connection closed".to_string())
+}
+
 rustler::atoms! {
     ok,
     error,
@@ -86,59 +116,523 @@ rustler::atoms! {
     immediate_transaction_mode,
     memory_allocator,
     duckdb,
-    erlang
+    erlang,
+    reader_pool_size,
+    max_spill,
+    continue_atom = "continue",
+    done
+}
+
+/// Default number of standing reader connections a `Pool` keeps open, used
+/// when `open`'s config map doesn't set `reader_pool_size`.
+const DEFAULT_READER_POOL_SIZE: usize = 4;
+/// Default cap on extra reader connections a `Pool` will spill beyond its
+/// standing pool when every reader is busy, used when `open`'s config map
+/// doesn't set `max_spill`.
+const DEFAULT_MAX_SPILL: usize = 4;
+
+/// Commands understood by a connection's owner thread. Every variant
+/// carries a one-shot `mpsc` reply channel so the NIF that sent it can
+/// block until the owner thread has finished the operation on its own
+/// stack, where the `Connection` (and anything borrowed from it) actually
+/// lives.
+enum ConnCommand {
+    /// Prepare a statement and keep it alive in the owner thread's
+    /// `statements` map under the globally-unique id the caller allocated.
+    /// No Elixir-facing NIF sends this command, and no command executes a
+    /// stored statement.
+    Prepare {
+        id: u64,
+        sql: String,
+        reply: mpsc::Sender<Result<(), String>>,
+    },
+    /// Prepare and immediately execute a statement, keeping the resulting
+    /// `Rows` open under `id` for later fetching. `id` is allocated by the
+    /// caller (not this thread) because a connection's reads can be spread
+    /// across several reader threads that would otherwise each hand out
+    /// colliding ids from their own local counters.
+    Query {
+        id: u64,
+        sql: String,
+        reply: mpsc::Sender<Result<(), String>>,
+    },
+    /// Advance an open query's `Rows` by up to `max_rows`, decoding each
+    /// row to an owned `DuckValue` row (an `Env`-free stand-in, since the
+    /// `fetch_chunk` NIF's `Env` can't cross the channel) and reporting
+    /// whether the query ran dry. A dry query is dropped from this
+    /// thread's `open_queries` before the reply is sent.
+    FetchChunk {
+        query_id: u64,
+        max_rows: u32,
+        reply: mpsc::Sender<Result<(Vec<Vec<DuckValue>>, bool), String>>,
+    },
+    /// Read back the column names of an open query's result set.
+    ColumnNames {
+        query_id: u64,
+        reply: mpsc::Sender<Result<Vec<String>, String>>,
+    },
+    /// Open an `Appender` onto `table_name`, keeping it alive under `id`
+    /// for later `AppenderAddRows`/`AppenderFlush`/`AppenderClose` commands.
+    Appender {
+        id: u64,
+        table_name: String,
+        reply: mpsc::Sender<Result<(), String>>,
+    },
+    /// Append every row to the open appender, in order, as one batch.
+    AppenderAddRows {
+        appender_id: u64,
+        rows: Vec<Vec<DuckValue>>,
+        reply: mpsc::Sender<Result<(), String>>,
+    },
+    AppenderFlush {
+        appender_id: u64,
+        reply: mpsc::Sender<Result<(), String>>,
+    },
+    /// Flush and drop the appender, freeing its slot in this thread's
+    /// local map.
+    AppenderClose {
+        appender_id: u64,
+        reply: mpsc::Sender<Result<(), String>>,
+    },
+    /// Open a fresh connection to the same database by cloning this
+    /// thread's `Connection` and spawning a new owner thread for the
+    /// clone. Used to spill an extra reader when a `Pool`'s standing
+    /// readers are all checked out.
+    Spawn {
+        reply: mpsc::Sender<Result<mpsc::Sender<ConnCommand>, String>>,
+    },
+    /// Tear down the connection. The owner thread drops its `Connection`
+    /// (and every statement/query still open on it) and exits its loop.
+    Close { reply: mpsc::Sender<()> },
+    Version { reply: mpsc::Sender<String> },
+}
+
+/// An open result set together with the prepared statement that produced
+/// it. DuckDB's `Rows` borrows its `Statement`, so the statement has to
+/// stay alive and at a fixed address for as long as the `Rows` is kept
+/// around across separate `FetchChunk` commands. Both lifetimes are
+/// erased to `'static` here; this is sound only within the confines of
+/// `run_owner`, because:
+///   1. `statement` is heap-allocated via `Box`, so its address is stable
+///      even if the surrounding `HashMap` reallocates;
+///   2. neither field is ever sent across a thread boundary or outlives
+///      the owner thread's `Connection`;
+///   3. `rows` is declared before `statement`, so it's dropped first.
+struct OpenQuery {
+    rows: Rows<'static>,
+    statement: Box<Statement<'static>>,
+}
+
+fn open_query(connection: &Connection, sql: &str) -> duckdb::Result<OpenQuery> {
+    let mut statement: Box<Statement> = Box::new(connection.prepare(sql)?);
+    let statement_ptr: *mut Statement = statement.as_mut();
+    // SAFETY: see the `OpenQuery` doc comment above.
+    let rows = unsafe { (*statement_ptr).query([])? };
+    Ok(OpenQuery {
+        rows: unsafe { std::mem::transmute::<Rows, Rows<'static>>(rows) },
+        statement: unsafe {
+            std::mem::transmute::<Box<Statement>, Box<Statement<'static>>>(statement)
+        },
+    })
+}
+
+/// Advance `open`'s `Rows` by up to `max_rows`, decoding every column of
+/// every row with `Value` (DuckDB's own catch-all column type) and
+/// narrowing that down to the handful of shapes `DuckValue` knows how to
+/// carry across the channel and back out as an Elixir term.
+fn fetch_rows(open: &mut OpenQuery, max_rows: u32) -> duckdb::Result<(Vec<Vec<DuckValue>>, bool)> {
+    let column_count = open.statement.column_count();
+    let mut batch = Vec::new();
+    let mut done = false;
+    for _ in 0..max_rows {
+        match open.rows.next()? {
+            Some(row) => {
+                let values = (0..column_count)
+                    .map(|i| row.get::<usize, Value>(i).map(value_to_duck_value))
+                    .collect::<duckdb::Result<Vec<_>>>()?;
+                batch.push(values);
+            }
+            None => {
+                done = true;
+                break;
+            }
+        }
+    }
+    Ok((batch, done))
+}
+
+/// Body of a connection's owner thread: takes ownership of an
+/// already-opened `Connection` and services commands off `commands` until
+/// told to close (or until every sender is dropped). `statements` and
+/// `open_queries` are local to this stack frame, exactly like
+/// `connection` itself, so everything borrowed from `connection` is valid
+/// for as long as this function runs.
+fn run_owner(connection: Connection, commands: mpsc::Receiver<ConnCommand>) {
+    let mut statements: HashMap<u64, Box<Statement>> = HashMap::new();
+    let mut open_queries: HashMap<u64, OpenQuery> = HashMap::new();
+    let mut appenders: HashMap<u64, Appender> = HashMap::new();
+
+    for command in commands {
+        match command {
+            ConnCommand::Prepare { id, sql, reply } => {
+                let result = connection
+                    .prepare(&sql)
+                    .map(|stmt| {
+                        statements.insert(id, Box::new(stmt));
+                    })
+                    .map_err(|err| err.to_string());
+                let _ = reply.send(result);
+            }
+            ConnCommand::Query { id, sql, reply } => {
+                let result = open_query(&connection, &sql)
+                    .map(|open| {
+                        open_queries.insert(id, open);
+                    })
+                    .map_err(|err| err.to_string());
+                let _ = reply.send(result);
+            }
+            ConnCommand::FetchChunk {
+                query_id,
+                max_rows,
+                reply,
+            } => {
+                let result = match open_queries.get_mut(&query_id) {
+                    Some(open) => fetch_rows(open, max_rows).map_err(|err| err.to_string()),
+                    None => Err("unknown query".to_string()),
+                };
+                if matches!(result, Ok((_, true))) {
+                    open_queries.remove(&query_id);
+                }
+                let _ = reply.send(result);
+            }
+            ConnCommand::ColumnNames { query_id, reply } => {
+                let result = match open_queries.get(&query_id) {
+                    Some(open) => Ok(open.statement.column_names()),
+                    None => Err("unknown query".to_string()),
+                };
+                let _ = reply.send(result);
+            }
+            ConnCommand::Appender { id, table_name, reply } => {
+                let result = connection
+                    .appender(&table_name)
+                    .map(|appender| {
+                        appenders.insert(id, appender);
+                    })
+                    .map_err(|err| err.to_string());
+                let _ = reply.send(result);
+            }
+            ConnCommand::AppenderAddRows { appender_id, rows, reply } => {
+                let result = match appenders.get_mut(&appender_id) {
+                    Some(appender) => rows
+                        .iter()
+                        .try_for_each(|row| appender.append_row(duckdb::params_from_iter(row.iter())))
+                        .map_err(|err| err.to_string()),
+                    None => Err("unknown appender".to_string()),
+                };
+                let _ = reply.send(result);
+            }
+            ConnCommand::AppenderFlush { appender_id, reply } => {
+                let result = match appenders.get_mut(&appender_id) {
+                    Some(appender) => appender.flush().map_err(|err| err.to_string()),
+                    None => Err("unknown appender".to_string()),
+                };
+                let _ = reply.send(result);
+            }
+            ConnCommand::AppenderClose { appender_id, reply } => {
+                let result = match appenders.remove(&appender_id) {
+                    Some(mut appender) => appender.flush().map_err(|err| err.to_string()),
+                    None => Err("unknown appender".to_string()),
+                };
+                let _ = reply.send(result);
+            }
+            ConnCommand::Spawn { reply } => {
+                let result = connection
+                    .try_clone()
+                    .map(|cloned| {
+                        let (tx, rx) = mpsc::channel();
+                        thread::spawn(move || run_owner(cloned, rx));
+                        tx
+                    })
+                    .map_err(|err| err.to_string());
+                let _ = reply.send(result);
+            }
+            ConnCommand::Close { reply } => {
+                let _ = reply.send(());
+                break;
+            }
+            ConnCommand::Version { reply } => {
+                let _ = reply.send(connection.version().unwrap_or_default());
+            }
+        }
+    }
+}
+
+/// A connection to an owner thread's command channel, handed out by a
+/// `Pool`'s reader recycler.
+struct ReaderHandle {
+    commands: mpsc::Sender<ConnCommand>,
+}
+
+/// A database's full connection pool: one writer connection that
+/// serializes every mutating statement, plus a recycling pool of reader
+/// connections that can each run a `SELECT` fully concurrently with the
+/// writer and with each other.
+///
+/// `idle_readers` is the recycler: `checkout_reader` pops a handle off it
+/// (spilling a freshly-cloned connection if it's momentarily empty and
+/// the pool is under `max_spill`), and `ReaderGuard::drop` pushes the
+/// handle back so steady-state load reuses connections instead of
+/// constantly opening and closing them.
+struct Pool {
+    writer: mpsc::Sender<ConnCommand>,
+    thread_count: u32,
+    idle_readers: Mutex<mpsc::Receiver<ReaderHandle>>,
+    return_reader: mpsc::Sender<ReaderHandle>,
+    spilled: AtomicUsize,
+    max_spill: usize,
+    /// Set while an explicit `BEGIN`/`START TRANSACTION` is open on the
+    /// writer and cleared on `COMMIT`/`ROLLBACK`. A pooled reader has no
+    /// visibility into the writer's uncommitted changes, so every
+    /// statement — `SELECT`s included — has to stay on the writer for the
+    /// duration of the transaction or it would silently read stale or
+    /// inconsistent data instead of the caller's own writes.
+    in_transaction: AtomicBool,
+}
+
+/// Checked-out reader handle. Goes back to the pool's recycler on drop so
+/// the next `SELECT` can reuse the connection instead of spilling a new
+/// one.
+struct ReaderGuard {
+    handle: Option<ReaderHandle>,
+    return_reader: mpsc::Sender<ReaderHandle>,
+}
+
+impl Drop for ReaderGuard {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = self.return_reader.send(handle);
+        }
+    }
+}
+
+fn checkout_reader(pool: &Pool) -> Result<ReaderGuard, String> {
+    let idle = lock_mutex(&pool.idle_readers);
+    let guard = |handle| ReaderGuard {
+        handle: Some(handle),
+        return_reader: pool.return_reader.clone(),
+    };
+    match idle.try_recv() {
+        Ok(handle) => Ok(guard(handle)),
+        Err(mpsc::TryRecvError::Disconnected) => Err("connection closed".to_string()),
+        Err(mpsc::TryRecvError::Empty) => {
+            if pool.spilled.fetch_add(1, Ordering::SeqCst) < pool.max_spill {
+                match roundtrip(&pool.writer, |reply| ConnCommand::Spawn { reply })? {
+                    Ok(commands) => Ok(guard(ReaderHandle { commands })),
+                    Err(err) => {
+                        pool.spilled.fetch_sub(1, Ordering::SeqCst);
+                        Err(err)
+                    }
+                }
+            } else {
+                pool.spilled.fetch_sub(1, Ordering::SeqCst);
+                // Every standing reader and every spilled extra is busy;
+                // wait for whichever one finishes first instead of
+                // growing the pool past `max_spill`.
+                idle.recv().map(guard).map_err(|_| "connection closed".to_string())
+            }
+        }
+    }
+}
+
+/// A rough, non-parsing heuristic for routing a statement to the reader
+/// pool instead of the writer: only statements that can't possibly mutate
+/// the database are read-only. Anything else (including ones we don't
+/// recognize) takes the writer so a misclassification can never let a
+/// write run concurrently with other connections.
+///
+/// `with` is deliberately not on the safe list: DuckDB allows a CTE to
+/// wrap a data-modifying statement (e.g. `WITH t AS (DELETE FROM ...
+/// RETURNING *) SELECT * FROM t`), and this heuristic never looks past
+/// the first keyword to know which kind it's looking at. `explain` stays
+/// read-only only for the plain form; `EXPLAIN ANALYZE` actually runs the
+/// wrapped statement to gather timings, so a data-modifying statement
+/// under it must still go to the writer.
+fn is_read_only(sql: &str) -> bool {
+    let mut words = sql_keywords(sql);
+    match words.next().unwrap_or_default().as_str() {
+        "select" | "pragma" | "describe" | "show" => true,
+        "explain" => words.next().as_deref() != Some("analyze"),
+        _ => false,
+    }
+}
+
+/// Lowercased, whitespace/paren/semicolon-split keywords of `sql`, in
+/// order. Shared by `is_read_only` and the transaction-boundary checks
+/// below so they all agree on where a statement's "first word" starts and
+/// ends.
+fn sql_keywords(sql: &str) -> impl Iterator<Item = String> + '_ {
+    sql.trim_start()
+        .split(|c: char| c.is_whitespace() || c == '(' || c == ';')
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_ascii_lowercase())
+}
+
+/// Whether `sql` opens an explicit transaction (`BEGIN`/`BEGIN
+/// TRANSACTION`/`START TRANSACTION`).
+fn begins_transaction(sql: &str) -> bool {
+    matches!(sql_keywords(sql).next().as_deref(), Some("begin") | Some("start"))
+}
+
+/// Whether `sql` closes an explicit transaction (`COMMIT`/`ROLLBACK`).
+fn ends_transaction(sql: &str) -> bool {
+    matches!(sql_keywords(sql).next().as_deref(), Some("commit") | Some("rollback"))
+}
+
+/// An owned, `Send`able stand-in for a single Elixir term that's been
+/// decoded into the DuckDB value it represents. `Term`s can't cross the
+/// channel to an owner thread (they're tied to the calling `Env`), so
+/// `appender_add_rows` decodes every cell up front and only ever sends
+/// `DuckValue`s down the wire.
+#[derive(Debug)]
+enum DuckValue {
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Text(String),
+    Blob(Vec<u8>),
+    Null,
+}
+
+impl ToSql for DuckValue {
+    fn to_sql(&self) -> duckdb::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            DuckValue::Integer(v) => ToSqlOutput::from(*v),
+            DuckValue::Float(v) => ToSqlOutput::from(*v),
+            DuckValue::Boolean(v) => ToSqlOutput::from(*v),
+            DuckValue::Text(v) => ToSqlOutput::from(v.clone()),
+            DuckValue::Blob(v) => ToSqlOutput::from(v.clone()),
+            DuckValue::Null => ToSqlOutput::from(Value::Null),
+        })
+    }
 }
 
-struct RustlerConn<'a, 'b: 'a> {
-    connection: &'a Connection,
-    queries: HashMap<u64, Mutex<Rows<'b>>>,
-    lifetime_queries: u64,
-    statements: HashMap<u64, Mutex<Statement<'b>>>,
-    lifetime_statements: u64
+/// Decode a single Elixir term into the `DuckValue` it represents, trying
+/// the common cases roughly in order of how cheap/likely they are:
+/// `nil`, booleans, integers, floats, then strings/binaries.
+fn decode_value(env: Env, term: Term) -> Result<DuckValue, String> {
+    if term == nil().encode(env) {
+        return Ok(DuckValue::Null);
+    }
+    if let Ok(v) = bool::decode(term) {
+        return Ok(DuckValue::Boolean(v));
+    }
+    if let Ok(v) = i64::decode(term) {
+        return Ok(DuckValue::Integer(v));
+    }
+    if let Ok(v) = f64::decode(term) {
+        return Ok(DuckValue::Float(v));
+    }
+    if let Ok(v) = String::decode(term) {
+        return Ok(DuckValue::Text(v));
+    }
+    if let Ok(v) = Binary::decode(term) {
+        return Ok(DuckValue::Blob(v.as_slice().to_vec()));
+    }
+    Err("unsupported row value".to_string())
 }
-unsafe impl Sync for RustlerConn<'_, '_> {}
-unsafe impl Send for RustlerConn<'_, '_> {}
 
-struct Conns<'a, 'b> {connections: HashMap<u64, (Mutex<RustlerConn<'a, 'b>>, u32)>, lifetime_connections: u64}
+/// Narrow one of DuckDB's column values down to the handful of shapes
+/// `DuckValue` carries back across the channel. Every integer width that
+/// fits losslessly in `i64` collapses to `Integer(i64)` and every float
+/// width to `Float(f64)`, the same simplification `decode_value` makes in
+/// the other direction; `HugeInt` and `UBigInt` can both hold values
+/// outside `i64`'s range, so a cast would silently wrap/truncate them
+/// (a `UBIGINT` of `u64::MAX` coming back as `-1` with no indication
+/// anything went wrong) — they fall through to the same honest `Debug`
+/// rendering as everything else DuckDB can produce that isn't taught its
+/// own `DuckValue` arm (dates, decimals, nested types, ...).
+fn value_to_duck_value(value: Value) -> DuckValue {
+    match value {
+        Value::Null => DuckValue::Null,
+        Value::Boolean(v) => DuckValue::Boolean(v),
+        Value::TinyInt(v) => DuckValue::Integer(v as i64),
+        Value::SmallInt(v) => DuckValue::Integer(v as i64),
+        Value::Int(v) => DuckValue::Integer(v as i64),
+        Value::BigInt(v) => DuckValue::Integer(v),
+        Value::UTinyInt(v) => DuckValue::Integer(v as i64),
+        Value::USmallInt(v) => DuckValue::Integer(v as i64),
+        Value::UInt(v) => DuckValue::Integer(v as i64),
+        Value::Float(v) => DuckValue::Float(v as f64),
+        Value::Double(v) => DuckValue::Float(v),
+        Value::Text(v) => DuckValue::Text(v),
+        Value::Blob(v) => DuckValue::Blob(v),
+        other => DuckValue::Text(format!("{:?}", other)),
+    }
+}
+
+/// Encode a `DuckValue` fetched back from an owner thread into the
+/// Elixir term it represents, the inverse of `decode_value`.
+fn encode_value<'a>(env: Env<'a>, value: DuckValue) -> Term<'a> {
+    match value {
+        DuckValue::Null => nil().encode(env),
+        DuckValue::Boolean(v) => v.encode(env),
+        DuckValue::Integer(v) => v.encode(env),
+        DuckValue::Float(v) => v.encode(env),
+        DuckValue::Text(v) => v.encode(env),
+        DuckValue::Blob(v) => {
+            let mut binary = rustler::types::OwnedBinary::new(v.len()).expect("allocate binary");
+            binary.as_mut_slice().copy_from_slice(&v);
+            Binary::from_owned(binary, env).encode(env)
+        }
+    }
+}
+
+struct Conns {
+    connections: HashMap<u64, Arc<Pool>>,
+    lifetime_connections: u64,
+}
 static CONNECTIONS: RwLock<Option<Conns>> = RwLock::new(None);
 
-struct Qrys<'a> {queries: HashMap<u64, Mutex<Rows<'a>>>, lifetime_queries: u64}
-unsafe impl Sync for Qrys<'_> {}
-unsafe impl Send for Qrys<'_> {}
-static QUERIES: RwLock<Option<Qrys>> = RwLock::new(None);
-/*struct RustlerConnection {connection: Mutex<Connection>, thread_count: u32}
-unsafe impl Sync for RustlerConnection {}
-unsafe impl Send for RustlerConnection {}
-struct Stmt<'a> {statement: Mutex<Statement<'a>>}
-unsafe impl Sync for Stmt<'_> {}
-unsafe impl Send for Stmt<'_> {}
-struct QueryResult {result: Mutex<Rows<'static>>}
-unsafe impl Sync for QueryResult {}
-unsafe impl Send for QueryResult {}
-struct Append {append: Appender<'static>}
-unsafe impl Sync for Append {}
-unsafe impl Send for Append {}*/
+/// Maps a live query id back to the `ConnCommand` sender for the thread
+/// (writer or a specific reader) that holds its `Rows`, so
+/// `fetch_chunk`/`column_names` can take just a `query_id` the way Elixir
+/// callers expect, without also needing the `conn_id`.
+static QUERY_OWNERS: RwLock<Option<HashMap<u64, mpsc::Sender<ConnCommand>>>> = RwLock::new(None);
+
+/// Query/statement ids are allocated here rather than by the owning
+/// thread's own counter, because a single connection's reads can be
+/// spread across several reader threads that would otherwise each hand
+/// out colliding ids from their own local counters.
+static NEXT_QUERY_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Maps a live appender id back to the `ConnCommand` sender for the
+/// writer thread that owns it (appenders always go through the writer,
+/// never a reader, since they mutate the table).
+static APPENDER_OWNERS: RwLock<Option<HashMap<u64, mpsc::Sender<ConnCommand>>>> = RwLock::new(None);
+static NEXT_APPENDER_ID: AtomicU64 = AtomicU64::new(1);
 
 pub fn load(env: Env, _term: Term) -> bool {
-    let mut test = CONNECTIONS.write().unwrap();
-    let _ = test.insert(Conns{connections: HashMap::new(), lifetime_connections: 0});
-    let mut test2 = QUERIES.write().unwrap();
-    let _ = test2.insert(Qrys{queries: HashMap::new(), lifetime_queries: 0});
-    //rustler::resource!(RustlerConnection, env);
-    //rustler::resource!(Stmt<'static>, env);
-    //rustler::resource!(QueryResult, env);
-    //rustler::resource!(Append, env);
+    let mut test = write_lock(&CONNECTIONS);
+    let _ = test.insert(Conns {
+        connections: HashMap::new(),
+        lifetime_connections: 0,
+    });
+    let mut test2 = write_lock(&QUERY_OWNERS);
+    let _ = test2.insert(HashMap::new());
+    let mut test3 = write_lock(&APPENDER_OWNERS);
+    let _ = test3.insert(HashMap::new());
+    let _ = env;
     true
 }
 
 #[rustler::nif(schedule = "DirtyIo")]
 fn open<'a>(env: Env<'a>, path: &str, config_settings: HashMap<Term<'a>, Term<'a>>) -> Term<'a> {
-    
+
     let mut thread_count = 0;
     let mut config = Config::default();
     config = match config_settings.get(&access_mode().encode(env)) {
         Some(val) => match Atom::decode(*val) {
-            Ok(decoded) => 
+            Ok(decoded) =>
                 if decoded == automatic() {
                     Config::access_mode(config, AccessMode::Automatic).unwrap()
                 }
@@ -155,19 +649,25 @@ fn open<'a>(env: Env<'a>, path: &str, config_settings: HashMap<Term<'a>, Term<'a
         None => config
     };
     config = match config_settings.get(&maximum_memory().encode(env)) {
-        Some(val) => Config::max_memory(config, &(u32::decode(*val).unwrap().to_string() + "b")).unwrap(),
+        Some(val) => match u32::decode(*val) {
+            Ok(decoded) => Config::max_memory(config, &(decoded.to_string() + "b")).unwrap(),
+            Err(_) => return (error(), "invalid maximum_memory".to_string()).encode(env),
+        },
         None => config
     };
     config = match config_settings.get(&maximum_threads().encode(env)) {
-        Some(val) => {
-            thread_count = u32::decode(*val).unwrap();
-            Config::threads(config, u32::decode(*val).unwrap() as i64).unwrap()
+        Some(val) => match u32::decode(*val) {
+            Ok(decoded) => {
+                thread_count = decoded;
+                Config::threads(config, decoded as i64).unwrap()
+            },
+            Err(_) => return (error(), "invalid maximum_threads".to_string()).encode(env),
         },
         None => config
     };
     config = match config_settings.get(&default_order_type().encode(env)) {
         Some(val) => match Atom::decode(*val) {
-            Ok(decoded) => 
+            Ok(decoded) =>
                 if decoded == asc() {
                     Config::default_order(config, DefaultOrder::Asc).unwrap()
                 }
@@ -182,7 +682,7 @@ fn open<'a>(env: Env<'a>, path: &str, config_settings: HashMap<Term<'a>, Term<'a
     };
     config = match config_settings.get(&default_null_order().encode(env)) {
         Some(val) => match Atom::decode(*val) {
-            Ok(decoded) => 
+            Ok(decoded) =>
                 if decoded == nulls_firs() {
                     Config::default_null_order(config, DefaultNullOrder::NullsFirst).unwrap()
                 }
@@ -196,156 +696,403 @@ fn open<'a>(env: Env<'a>, path: &str, config_settings: HashMap<Term<'a>, Term<'a
         None => config
     };
     config = match config_settings.get(&enable_external_access().encode(env)) {
-        Some(val) => Config::enable_external_access(config, bool::decode(*val).unwrap()).unwrap(),
+        Some(val) => match bool::decode(*val) {
+            Ok(decoded) => Config::enable_external_access(config, decoded).unwrap(),
+            Err(_) => return (error(), "invalid enable_external_access".to_string()).encode(env),
+        },
         None => config
     };
     config = match config_settings.get(&object_cache_enable().encode(env)) {
-        Some(val) => Config::enable_object_cache(config, bool::decode(*val).unwrap()).unwrap(),
+        Some(val) => match bool::decode(*val) {
+            Ok(decoded) => Config::enable_object_cache(config, decoded).unwrap(),
+            Err(_) => return (error(), "invalid object_cache_enable".to_string()).encode(env),
+        },
         None => config
     };
     config = match config_settings.get(&allow_unsigned_extensions().encode(env)) {
-        Some(val) => match bool::decode(*val).unwrap() {
-            true => Config::allow_unsigned_extensions(config).unwrap(),
-            false => config
+        Some(val) => match bool::decode(*val) {
+            Ok(true) => Config::allow_unsigned_extensions(config).unwrap(),
+            Ok(false) => config,
+            Err(_) => return (error(), "invalid allow_unsigned_extensions".to_string()).encode(env),
         },
         None => config
     };
 
 
+    let pool_size = match config_settings.get(&reader_pool_size().encode(env)) {
+        Some(val) => match u32::decode(*val) {
+            Ok(decoded) => decoded as usize,
+            Err(_) => return (error(), "invalid reader_pool_size".to_string()).encode(env),
+        },
+        None => DEFAULT_READER_POOL_SIZE,
+    };
+    let spill_limit = match config_settings.get(&max_spill().encode(env)) {
+        Some(val) => match u32::decode(*val) {
+            Ok(decoded) => decoded as usize,
+            Err(_) => return (error(), "invalid max_spill".to_string()).encode(env),
+        },
+        None => DEFAULT_MAX_SPILL,
+    };
+
     let conn = match path{
         ":memory:"=> Connection::open_in_memory_with_flags(config),
         _=>Connection::open_with_flags(path, config)
     };
     match conn {
         Ok(connection) => {
-            let mut test = CONNECTIONS.write().unwrap();
-            let mut conn_object = &mut test.as_mut().unwrap();
-            conn_object.lifetime_connections+= 1;
-            conn_object.connections.insert(conn_object.lifetime_connections, (Mutex::new(connection), thread_count));
+            // Spin up the standing reader pool first, cloning off the
+            // writer connection before it's moved into its owner thread.
+            let (return_reader, idle_readers) = mpsc::channel();
+            let mut spawned_readers = Vec::with_capacity(pool_size);
+            for _ in 0..pool_size {
+                match connection.try_clone() {
+                    Ok(reader_conn) => {
+                        let (commands, rx) = mpsc::channel();
+                        thread::spawn(move || run_owner(reader_conn, rx));
+                        spawned_readers.push(commands.clone());
+                        let _ = return_reader.send(ReaderHandle { commands });
+                    }
+                    Err(err) => {
+                        // Readers spawned before this failure have no
+                        // conn_id to ever be reached by, so they'd
+                        // otherwise sit there forever with a live DuckDB
+                        // connection; close them out before bailing.
+                        for commands in spawned_readers {
+                            close_connection(&commands);
+                        }
+                        return (error(), err.to_string()).encode(env);
+                    }
+                }
+            }
+
+            let (writer, rx) = mpsc::channel();
+            thread::spawn(move || run_owner(connection, rx));
+
+            let pool = Pool {
+                writer,
+                thread_count,
+                idle_readers: Mutex::new(idle_readers),
+                return_reader,
+                spilled: AtomicUsize::new(0),
+                max_spill: spill_limit,
+                in_transaction: AtomicBool::new(false),
+            };
+
+            let mut test = write_lock(&CONNECTIONS);
+            let conn_object = test.as_mut().unwrap();
+            conn_object.lifetime_connections += 1;
+            conn_object.connections.insert(conn_object.lifetime_connections, Arc::new(pool));
             (ok(), conn_object.lifetime_connections).encode(env)
         },
         Err(err) => (error(), err.to_string()).encode(env)
     }
 }
 
+fn close_connection(commands: &mpsc::Sender<ConnCommand>) {
+    // If the owner thread is already gone the send fails and there's
+    // nothing left to wait on; either way the connection is closed.
+    let _ = roundtrip(commands, |reply| ConnCommand::Close { reply });
+}
+
 #[rustler::nif(schedule = "DirtyIo")]
 fn close(env: Env, conn_id: u64) -> Term {
-    //Ownership is still a little weird here, so what I'm doing is removing the entire hashmap entry for the element.
-    //This *should* tell the mutex to destroy itself, which should by extension tell the connection to close.
-    //If I were able to, the command after .unwrap().connections should look like the following line:
-    //.get(&conn_id).unwrap().0.lock().unwrap().close();
-    //That would, for sure, close the connection directly. But that transfers ownership, so this method should work better.
-    CONNECTIONS.write().unwrap().as_mut().unwrap().connections.remove(&conn_id);
+    let pool = write_lock(&CONNECTIONS).as_mut().unwrap().connections.remove(&conn_id);
+    if let Some(pool) = pool {
+        close_connection(&pool.writer);
+        // Close every reader currently idle in the pool. Any still
+        // checked out by an in-flight query are simply dropped once that
+        // query finishes: with the pool gone there's nowhere left to
+        // return them to, so their owner threads exit on their own.
+        let idle_readers = lock_mutex(&pool.idle_readers);
+        while let Ok(handle) = idle_readers.try_recv() {
+            close_connection(&handle.commands);
+        }
+    }
     ok().to_term(env)
 }
 
 #[rustler::nif(schedule = "DirtyIo")]
 fn query<'a>(env: Env<'a>, conn_id: u64, qry: &str, params: Vec<Term>) -> Term<'a> {
-    let mut test = CONNECTIONS.read().unwrap();
-    let mut conn_object = test.as_ref().unwrap().connections.get(&conn_id).unwrap().0.lock().unwrap();
-    match conn_object.prepare(qry) {
-        Ok(mut stmt) => match stmt.query([]) {
-            Ok(result) => {
-                let mut test2 = QUERIES.write().unwrap();
-                let mut qry_object = test2.as_mut().unwrap();
-                qry_object.lifetime_queries += 1;
-                qry_object.queries.insert(qry_object.lifetime_queries, Mutex::new(result));
-                (ok(), qry_object.lifetime_queries).encode(env)
-            },
-            Err(err) => (error(), err.to_string()).encode(env)
+    let _ = params;
+    // Clone the `Arc<Pool>` out and drop the global lock immediately: the
+    // rest of this NIF blocks on the owner thread for as long as the query
+    // runs, and holding a `CONNECTIONS` read-lock across that would stall
+    // any concurrent `open`/`close` (which need the write lock) on an
+    // entirely unrelated connection until this query finishes.
+    let pool = match read_lock(&CONNECTIONS).as_ref().unwrap().connections.get(&conn_id) {
+        Some(pool) => pool.clone(),
+        None => return (error(), "unknown connection").encode(env),
+    };
+
+    // A reader can't see the writer's not-yet-committed changes, so while
+    // a transaction is open on the writer every statement has to stay
+    // there too, regardless of `is_read_only` — otherwise a `SELECT`
+    // inside a caller's own transaction would silently read stale data
+    // off an unrelated pooled connection instead of the writer's own
+    // uncommitted writes.
+    let stay_on_writer = pool.in_transaction.load(Ordering::SeqCst);
+    let (commands, _reader_guard) = if !stay_on_writer && is_read_only(qry) {
+        match checkout_reader(&pool) {
+            Ok(guard) => (guard.handle.as_ref().unwrap().commands.clone(), Some(guard)),
+            Err(err) => return (error(), err).encode(env),
         }
-        Err(err) => (error(), err.to_string()).encode(env),
+    } else {
+        (pool.writer.clone(), None)
+    };
+
+    let query_id = NEXT_QUERY_ID.fetch_add(1, Ordering::SeqCst);
+    let sql = qry.to_string();
+    match roundtrip(&commands, |reply| ConnCommand::Query { id: query_id, sql, reply }) {
+        Ok(Ok(())) => {
+            if begins_transaction(qry) {
+                pool.in_transaction.store(true, Ordering::SeqCst);
+            } else if ends_transaction(qry) {
+                pool.in_transaction.store(false, Ordering::SeqCst);
+            }
+            write_lock(&QUERY_OWNERS).as_mut().unwrap().insert(query_id, commands);
+            (ok(), query_id).encode(env)
+        },
+        Ok(Err(err)) | Err(err) => (error(), err).encode(env),
     }
 }
 
 /*#[rustler::nif(schedule = "DirtyIo")]
-fn prepare_statement<'a>(env: Env<'a>, arc_connection: ResourceArc<RustlerConnection>, statement: &str) -> Term<'a> {
-    let mut connection: Connection = *arc_connection.connection.lock().unwrap();
-    match connection.prepare(statement) {
-        Ok(statement) => (ok(), ResourceArc::new(Stmt{statement: &statement})).encode(env),
-        Err(err) => (error(), err.to_string()).encode(env),
-    }
+fn prepare_statement<'a>(env: Env<'a>, conn_id: u64, statement: &str) -> Term<'a> {
+    Term{term: 0, env:env}
 }
 
 #[rustler::nif(schedule = "DirtyIo")]
-fn execute_statement<'a>(env: Env<'a>, statement: ResourceArc<Stmt>, params: Vec<Term>) -> Term<'a> {
-    let mut stmt: &Statement = statement.statement;
-    match stmt.query([]) {
-        Ok(result) => (ok(), ResourceArc::new(QueryResult{result: &result})).encode(env),
-        Err(err) => (error(), err.to_string()).encode(env),
-    }
+fn execute_statement<'a>(env: Env<'a>, statement_id: u64, params: Vec<Term>) -> Term<'a> {
+    Term{term: 0, env:env}
 }
 
 #[rustler::nif(schedule = "DirtyIo")]
-fn get_column_names<'a>(env: Env<'a>, query_result: ResourceArc<QueryResult>) -> Term<'a> {
-    let mut query: &Rows = query_result.result;
-    match query.as_ref() {
-        Some(statement) => statement.column_names().encode(env),
-        None => make_tuple(env, &[]).encode(env)
-    }
+fn fetch_all<'a>(env: Env<'a>, query_id: u64) -> Term<'a> {
+    Term{term: 0, env:env}
 }
 
-#[rustler::nif(schedule = "DirtyIo")]
-fn fetch_chunk<'a>(env: Env<'a>, query_result: ResourceArc<QueryResult>) -> Term<'a> {
-    Term{term: 0, env:env}
+*/
+
+/// Look up an open query's owning sender by id, or `None` if `query_id`
+/// is unknown (already exhausted, or never existed).
+fn query_commands(query_id: u64) -> Option<mpsc::Sender<ConnCommand>> {
+    read_lock(&QUERY_OWNERS).as_ref().unwrap().get(&query_id).cloned()
 }
 
 #[rustler::nif(schedule = "DirtyIo")]
-fn fetch_all<'a>(env: Env<'a>, query_result: ResourceArc<QueryResult>) -> Term<'a> {
-    Term{term: 0, env:env}
+fn column_names<'a>(env: Env<'a>, query_id: u64) -> Term<'a> {
+    let commands = match query_commands(query_id) {
+        Some(commands) => commands,
+        None => return (error(), "unknown query").encode(env),
+    };
+    match roundtrip(&commands, |reply| ConnCommand::ColumnNames { query_id, reply }) {
+        Ok(Ok(names)) => (ok(), names).encode(env),
+        Ok(Err(err)) | Err(err) => (error(), err).encode(env),
+    }
 }
 
 #[rustler::nif(schedule = "DirtyIo")]
-fn appender(env: Env, arc_connection: ResourceArc<RustlerConnection>, table_name: String) -> Term {
-    let mut connection: Connection = *arc_connection.connection.lock().unwrap();
-    match connection.appender(&table_name) {
-        Ok(append) => (ok(), ResourceArc::new(Append{append: &append})).encode(env),
-        Err(err) => (error(), err.to_string()).encode(env),
+fn fetch_chunk<'a>(env: Env<'a>, query_id: u64, max_rows: u32) -> Term<'a> {
+    let commands = match query_commands(query_id) {
+        Some(commands) => commands,
+        None => return (error(), "unknown query").encode(env),
+    };
+    match roundtrip(&commands, |reply| ConnCommand::FetchChunk { query_id, max_rows, reply }) {
+        Ok(Ok((rows, done))) => {
+            if done {
+                write_lock(&QUERY_OWNERS).as_mut().unwrap().remove(&query_id);
+            }
+            let rows: Vec<Vec<Term>> = rows
+                .into_iter()
+                .map(|row| row.into_iter().map(|cell| encode_value(env, cell)).collect())
+                .collect();
+            let status = if done { done() } else { continue_atom() };
+            (ok(), rows, status).encode(env)
+        },
+        Ok(Err(err)) | Err(err) => (error(), err).encode(env),
     }
-    
 }
 
-#[rustler::nif(schedule = "DirtyIo")]
-fn appender_add_row<'a>(env: Env<'a>, appender: ResourceArc<Append>, row: Vec<String>) -> Term<'a> {
-    Term{term: 0, env:env}
+/// Look up a connection's writer sender by id, or `None` if `conn_id`
+/// doesn't name an open connection.
+fn writer_for(conn_id: u64) -> Option<mpsc::Sender<ConnCommand>> {
+    read_lock(&CONNECTIONS).as_ref().unwrap().connections.get(&conn_id).map(|pool| pool.writer.clone())
+}
+
+/// Look up an open appender's owning sender by id, or `None` if
+/// `appender_id` is unknown (already closed, or never existed).
+fn appender_commands(appender_id: u64) -> Option<mpsc::Sender<ConnCommand>> {
+    read_lock(&APPENDER_OWNERS).as_ref().unwrap().get(&appender_id).cloned()
 }
 
 #[rustler::nif(schedule = "DirtyIo")]
-fn appender_add_rows<'a>(env: Env<'a>, appender: ResourceArc<Append>, rows: Vec<Vec<String>>) -> Term<'a> {
-    Term{term: 0, env:env}
+fn appender<'a>(env: Env<'a>, conn_id: u64, table_name: String) -> Term<'a> {
+    let writer = match writer_for(conn_id) {
+        Some(writer) => writer,
+        None => return (error(), "unknown connection").encode(env),
+    };
+    let id = NEXT_APPENDER_ID.fetch_add(1, Ordering::SeqCst);
+    match roundtrip(&writer, |reply| ConnCommand::Appender { id, table_name, reply }) {
+        Ok(Ok(())) => {
+            write_lock(&APPENDER_OWNERS).as_mut().unwrap().insert(id, writer);
+            (ok(), id).encode(env)
+        },
+        Ok(Err(err)) | Err(err) => (error(), err).encode(env),
+    }
 }
 
 #[rustler::nif(schedule = "DirtyIo")]
-fn appender_flush<'a>(env: Env<'a>, appender: ResourceArc<Append>) -> Term<'a> {
-    let mut append: &Appender = appender.append;
-    append.flush();
-    ok().encode(env)
+fn appender_add_rows<'a>(env: Env<'a>, appender_id: u64, rows: Vec<Vec<Term<'a>>>) -> Term<'a> {
+    let rows: Result<Vec<Vec<DuckValue>>, String> = rows
+        .into_iter()
+        .map(|row| row.into_iter().map(|cell| decode_value(env, cell)).collect())
+        .collect();
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(err) => return (error(), err).encode(env),
+    };
+
+    let commands = match appender_commands(appender_id) {
+        Some(commands) => commands,
+        None => return (error(), "unknown appender").encode(env),
+    };
+    match roundtrip(&commands, |reply| ConnCommand::AppenderAddRows { appender_id, rows, reply }) {
+        Ok(Ok(())) => ok().encode(env),
+        Ok(Err(err)) | Err(err) => (error(), err).encode(env),
+    }
 }
 
 #[rustler::nif(schedule = "DirtyIo")]
-fn appender_close<'a>(env: Env<'a>, appender: ResourceArc<Append>) -> Term<'a> {
-    let term = appender_flush(env, appender);
-    let mut append: &Appender = appender.append;
-    drop(append);
-    term
+fn appender_flush(env: Env, appender_id: u64) -> Term {
+    let commands = match appender_commands(appender_id) {
+        Some(commands) => commands,
+        None => return (error(), "unknown appender").encode(env),
+    };
+    match roundtrip(&commands, |reply| ConnCommand::AppenderFlush { appender_id, reply }) {
+        Ok(Ok(())) => ok().encode(env),
+        Ok(Err(err)) | Err(err) => (error(), err).encode(env),
+    }
 }
 
-*/
+#[rustler::nif(schedule = "DirtyIo")]
+fn appender_close(env: Env, appender_id: u64) -> Term {
+    let commands = write_lock(&APPENDER_OWNERS).as_mut().unwrap().remove(&appender_id);
+    let commands = match commands {
+        Some(commands) => commands,
+        None => return (error(), "unknown appender").encode(env),
+    };
+    match roundtrip(&commands, |reply| ConnCommand::AppenderClose { appender_id, reply }) {
+        Ok(Ok(())) => ok().encode(env),
+        Ok(Err(err)) | Err(err) => (error(), err).encode(env),
+    }
+}
 
 #[rustler::nif(schedule = "DirtyCpu")]
 fn library_version(conn_id: u64) -> String {
-    let test = CONNECTIONS.read().unwrap();
-    let connection = test.as_ref().unwrap().connections.get(&conn_id).unwrap().0.lock().unwrap();
-    match connection.version() {
-        Ok(vsn) => vsn,
-        Err(_) => "".to_string()
+    match writer_for(conn_id) {
+        Some(writer) => roundtrip(&writer, |reply| ConnCommand::Version { reply }).unwrap_or_default(),
+        None => "".to_string(),
     }
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
 fn number_of_threads(conn_id: u64) -> u32 {
-    CONNECTIONS.read().unwrap().as_ref().unwrap().connections.get(&conn_id).unwrap().1
+    read_lock(&CONNECTIONS)
+        .as_ref()
+        .unwrap()
+        .connections
+        .get(&conn_id)
+        .map(|pool| pool.thread_count)
+        .unwrap_or(0)
 }
 
 
-rustler::init!("Elixir.DatabaseThing.NIF", [add, open, close, library_version, number_of_threads], load=load);
\ No newline at end of file
+rustler::init!(
+    "Elixir.DatabaseThing.NIF",
+    [
+        add,
+        open,
+        close,
+        query,
+        appender,
+        appender_add_rows,
+        appender_flush,
+        appender_close,
+        library_version,
+        number_of_threads,
+        column_names,
+        fetch_chunk
+    ],
+    load = load
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_read_only_routes_plain_reads_to_the_reader_pool() {
+        for sql in ["select 1", "  SELECT * FROM t", "pragma table_info(t)", "DESCRIBE t", "show tables"] {
+            assert!(is_read_only(sql), "{sql:?} should be read-only");
+        }
+    }
+
+    #[test]
+    fn is_read_only_keeps_writes_and_ctes_on_the_writer() {
+        for sql in [
+            "insert into t values (1)",
+            "update t set x = 1",
+            "delete from t",
+            "begin transaction",
+            "commit",
+            "with x as (delete from t returning *) select * from x",
+            "WITH x AS (INSERT INTO t VALUES (1) RETURNING *) SELECT * FROM x",
+        ] {
+            assert!(!is_read_only(sql), "{sql:?} should not be read-only");
+        }
+    }
+
+    #[test]
+    fn is_read_only_treats_explain_analyze_as_a_write() {
+        assert!(is_read_only("explain select * from t"));
+        assert!(is_read_only("EXPLAIN   SELECT * FROM t"));
+        assert!(!is_read_only("explain analyze select * from t"));
+        assert!(!is_read_only("EXPLAIN ANALYZE DELETE FROM t"));
+    }
+
+    #[test]
+    fn transaction_boundaries_are_detected_case_and_whitespace_insensitively() {
+        for sql in ["begin", "BEGIN TRANSACTION", "  start transaction"] {
+            assert!(begins_transaction(sql), "{sql:?} should begin a transaction");
+        }
+        for sql in ["commit", "ROLLBACK", "  commit;"] {
+            assert!(ends_transaction(sql), "{sql:?} should end a transaction");
+        }
+        assert!(!begins_transaction("select 1"));
+        assert!(!ends_transaction("select 1"));
+    }
+
+    #[test]
+    fn value_to_duck_value_preserves_every_integer_width_in_range() {
+        assert!(matches!(value_to_duck_value(Value::TinyInt(-1)), DuckValue::Integer(-1)));
+        assert!(matches!(value_to_duck_value(Value::BigInt(i64::MIN)), DuckValue::Integer(v) if v == i64::MIN));
+        assert!(matches!(value_to_duck_value(Value::UInt(42)), DuckValue::Integer(42)));
+    }
+
+    #[test]
+    fn value_to_duck_value_does_not_lossily_truncate_out_of_range_hugeint_or_ubigint() {
+        // u64::MAX cast to i64 would silently wrap around to -1; this must
+        // not happen, since there is no way for a caller to tell the
+        // wrapped value apart from a genuine -1.
+        match value_to_duck_value(Value::UBigInt(u64::MAX)) {
+            DuckValue::Integer(_) => panic!("UBigInt::MAX must not be narrowed to an i64"),
+            DuckValue::Text(_) => {}
+            other => panic!("unexpected DuckValue variant: {other:?}"),
+        }
+        match value_to_duck_value(Value::HugeInt(i128::MAX)) {
+            DuckValue::Integer(_) => panic!("HugeInt::MAX must not be narrowed to an i64"),
+            DuckValue::Text(_) => {}
+            other => panic!("unexpected DuckValue variant: {other:?}"),
+        }
+    }
+}